@@ -20,13 +20,18 @@ extern crate alloc;
 use std::ptr;
 use alloc::raw_vec::RawVec;
 use std::ops::{Index, IndexMut};
+use std::iter::FromIterator;
+use std::mem::MaybeUninit;
 
 ///! This opaque structure stores a lazy vector.
 pub struct LazyVec<T> {
     // Highest index currently stored.
     size: usize,
-    // Stack of actual vector values.
-    values: Vec<T>,
+    // Stack of actual vector values. Exactly the first
+    // `value_indices.len()` entries are initialized; the rest
+    // of the stack's capacity, like `Vec`'s own spare
+    // capacity, is not.
+    values: Vec<MaybeUninit<T>>,
     // Parallel stack indicating, for each value, what index
     // it is located at. Used during reads and writes to
     // check for need to initialize.
@@ -34,10 +39,13 @@ pub struct LazyVec<T> {
     // When a read or write is performed, this vector is
     // indirected through to do the initialization and/or
     // access.
-    indices: RawVec<usize>
+    indices: RawVec<usize>,
+    // If present, the value returned by reads of indices that
+    // have not been written, instead of panicking.
+    default: Option<T>
 }
 
-impl <T: Copy> LazyVec<T> {
+impl <T> LazyVec<T> {
 
     ///! Allocate a new empty `LazyVec`.
     pub fn new() -> LazyVec<T> {
@@ -45,7 +53,8 @@ impl <T: Copy> LazyVec<T> {
             size: 0,
             values: Vec::new(),
             value_indices: Vec::new(),
-            indices: RawVec::new()
+            indices: RawVec::new(),
+            default: None
         }
     }
 
@@ -56,7 +65,24 @@ impl <T: Copy> LazyVec<T> {
             size: 0,
             values: Vec::new(),
             value_indices: Vec::new(),
-            indices: RawVec::with_capacity(cap)
+            indices: RawVec::with_capacity(cap),
+            default: None
+        }
+    }
+
+    ///! Allocate a new empty `LazyVec` whose reads of
+    ///! untouched indices return `def` instead of panicking,
+    ///! echoing how `vec![x; n]` conceptually fills a vector
+    ///! with a single value. Writes still allocate a real
+    ///! stack slot, so a written element always shadows the
+    ///! default at its index.
+    pub fn with_default(def: T) -> LazyVec<T> {
+        LazyVec {
+            size: 0,
+            values: Vec::new(),
+            value_indices: Vec::new(),
+            indices: RawVec::new(),
+            default: Some(def)
         }
     }
 
@@ -72,11 +98,79 @@ impl <T: Copy> LazyVec<T> {
         self.size
     }
 
+    ///! Return a reference to the value at index `i`. If the
+    ///! index has never been written, this returns the
+    ///! default supplied via `with_default`, if any (matching
+    ///! `Index`/`value_ref`), or `None` otherwise.
+    pub fn get(&self, i: usize) -> Option<&T> {
+        if !self.contains_index(i) {
+            return self.default.as_ref();
+        }
+        // Get the putative index into the value stack.
+        let ix = unsafe {
+            // Get the correct pointer.
+            let ixptr = self.indices.ptr().offset(i as isize);
+            // Read the value there.
+            ptr::read(ixptr)
+        };
+        // Safe: `ix < values.len()` is guaranteed by
+        // `contains_index`, and every slot below
+        // `value_indices.len()` is initialized.
+        Some(unsafe { self.values[ix].assume_init_ref() })
+    }
+
+    ///! Return a mutable reference to the value at index `i`,
+    ///! or `None` if the index has never been written. Unlike
+    ///! `get`, this does not fall back to the `with_default`
+    ///! value even when one is set: the default is a single
+    ///! shared value, not a per-index slot, so there is nothing
+    ///! distinct at `i` to hand out a mutable reference to.
+    pub fn get_mut(&mut self, i: usize) -> Option<&mut T> {
+        if !self.contains_index(i) {
+            return None;
+        }
+        // Get the putative index into the value stack.
+        let ix = unsafe {
+            // Get the correct pointer.
+            let ixptr = self.indices.ptr().offset(i as isize);
+            // Read the value there.
+            ptr::read(ixptr)
+        };
+        // Safe: see `get`.
+        Some(unsafe { self.values[ix].assume_init_mut() })
+    }
+
+    ///! Return whether the value at index `i` has actually
+    ///! been written, as opposed to merely being covered by a
+    ///! `with_default` fallback. `remove` and `clear` only ever
+    ///! act on indices for which this is true.
+    pub fn contains_index(&self, i: usize) -> bool {
+        if i >= self.size {
+            return false;
+        }
+        // Get the putative index into the value stack.
+        let ix = unsafe {
+            // Get the correct pointer.
+            let ixptr = self.indices.ptr().offset(i as isize);
+            // Read the value there.
+            ptr::read(ixptr)
+        };
+        ix < self.values.len() && self.value_indices[ix] == i
+    }
+
     ///! Return a reference to the value at the given index.
+    ///! If the index has not been written and a default was
+    ///! supplied via `with_default`, the default is returned
+    ///! instead.
     ///!
     ///! XXX For now, panic on attempt to read from an
-    ///! uninitialized element.
+    ///! uninitialized element with no default.
     pub fn value_ref(&self, i: usize) -> &T {
+        if !self.contains_index(i) {
+            if let Some(ref def) = self.default {
+                return def;
+            }
+        }
         // XXX For now, fail if the value has not been
         // initialized (index off end of indices).
         assert!(i < self.size);
@@ -95,47 +189,149 @@ impl <T: Copy> LazyVec<T> {
         // value).
         assert!(self.value_indices[ix] == i);
         // Return the correct value from the value stack.
-        &self.values[ix]
+        unsafe { self.values[ix].assume_init_ref() }
     }
 
-    ///! Return a mutable reference to the value at index `i`.
-    ///! If no value previously existed, this will return a reference
-    ///! to uninitialized memory, making it unsafe.
-    pub unsafe fn value_ref_mut(&mut self, i: usize) -> &mut T {
+    ///! Return a mutable reference to the value at index `i`,
+    ///! creating a fresh slot filled with `T::default()` if
+    ///! none existed yet. A freshly-created slot is always
+    ///! written with a real value before its reference is
+    ///! handed out, so (unlike the old `Copy`-only version of
+    ///! this function) there is no way to observe
+    ///! uninitialized memory through the result, and the
+    ///! public write path no longer needs to be `unsafe`.
+    pub fn value_ref_mut(&mut self, i: usize) -> &mut T where T: Default {
         // Get the current index capacity.
         let cap = self.indices.cap();
         // If the current index capacity is too small, grow it.
         if i >= cap {
-            self.indices.reserve(cap, i - cap);
+            unsafe { self.indices.reserve(cap, i - cap + 1) };
         }
         // Get a pointer to the index element.
-        let ixptr = self.indices.ptr().offset(i as isize);
+        let ixptr = unsafe { self.indices.ptr().offset(i as isize) };
         // Get the current index.
-        let ix = ptr::read(ixptr);
+        let ix = unsafe { ptr::read(ixptr) };
         // Get the stack top.
         let nstacked = self.values.len();
         assert!(nstacked == self.value_indices.len());
         // If the value is uninitialized, initialize it.
         // Otherwise, just store it.
         if ix >= nstacked || self.value_indices[ix] != i {
-            // Save a place for a value on the stack.
-            self.values.reserve(1);
-            self.values.set_len(nstacked + 1);
+            // Save a place for a value on the stack, filled
+            // with a real (if throwaway) value so the slot is
+            // never exposed uninitialized.
+            self.values.push(MaybeUninit::new(T::default()));
             // Save the index of the value on the stack.
             self.value_indices.push(i);
             // Save the index of the value to the index.
-            ptr::write(ixptr, nstacked);
+            unsafe { ptr::write(ixptr, nstacked) };
             // Increase the size if necessary.
             if ix >= self.size {
                 self.size = i + 1
             };
-            &mut self.values[nstacked];
+            return unsafe { self.values[nstacked].assume_init_mut() };
         };
-        &mut self.values[ix]
+        unsafe { self.values[ix].assume_init_mut() }
+    }
+
+    ///! Return an iterator over the written elements, as
+    ///! `(index, &value)` pairs, in insertion order, until an
+    ///! element has been removed. `remove`'s swap-remove moves
+    ///! the last-inserted element into the removed slot's
+    ///! place on the dense stack, so once any element has been
+    ///! removed, iteration order no longer reflects insertion
+    ///! order. Cost is proportional to the number of written
+    ///! elements, not to `cap()`.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.value_indices.iter().cloned().zip(
+            // Safe: every slot up to `value_indices.len()` is
+            // initialized.
+            self.values.iter().map(|v| unsafe { v.assume_init_ref() })
+        )
+    }
+
+    ///! Return an iterator over the written elements, as
+    ///! `(index, &mut value)` pairs, in insertion order, until
+    ///! an element has been removed (see `iter`).
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut T)> {
+        self.value_indices.iter().cloned().zip(
+            // Safe: see `iter`.
+            self.values.iter_mut().map(|v| unsafe { v.assume_init_mut() })
+        )
+    }
+
+    ///! Remove all written elements, resetting the `LazyVec`
+    ///! to empty. This is O(written), since the (possibly
+    ///! huge) `indices` backing store does not need to be
+    ///! touched: a later read is still correctly rejected by
+    ///! the `ix < values.len()` check, because `ix` will
+    ///! be off the end of the now-empty `values` stack.
+    pub fn clear(&mut self) {
+        // `MaybeUninit` never runs `T`'s destructor on its
+        // own, so each initialized slot must be dropped by
+        // hand before the stack is emptied.
+        for v in self.values.iter_mut() {
+            unsafe { ptr::drop_in_place(v.as_mut_ptr()) };
+        }
+        self.values.clear();
+        self.value_indices.clear();
+        self.size = 0;
+    }
+
+    ///! Remove and return the value at index `i`, or `None`
+    ///! if the index has never been written. Uses a
+    ///! Briggs-Torczon swap-remove against the dense `values`
+    ///! stack, so this is O(1).
+    pub fn remove(&mut self, i: usize) -> Option<T> {
+        if !self.contains_index(i) {
+            return None;
+        }
+        // Get the putative index into the value stack.
+        let ixptr = unsafe { self.indices.ptr().offset(i as isize) };
+        let p = unsafe { ptr::read(ixptr) };
+        // Swap the last stack entry into the removed slot, if
+        // it is not the removed slot itself.
+        let last = self.values.len() - 1;
+        // Safe: `p` is a valid, initialized slot, per
+        // `contains_index`.
+        let value = unsafe { self.values.swap_remove(p).assume_init() };
+        self.value_indices.swap_remove(p);
+        if p != last {
+            // The entry that used to be on the top of the
+            // stack is now at position `p`; fix up its index
+            // to point back there.
+            let moved = self.value_indices[p];
+            let movedptr = unsafe { self.indices.ptr().offset(moved as isize) };
+            unsafe { ptr::write(movedptr, p) };
+        }
+        Some(value)
     }
 }
 
-impl<T: Copy> Index<usize> for LazyVec<T> {
+impl<T> Drop for LazyVec<T> {
+    ///! Free exactly the elements present in `value_indices`;
+    ///! the rest of the `values` stack's capacity was never
+    ///! initialized and must not be dropped.
+    fn drop(&mut self) {
+        for v in self.values.iter_mut() {
+            unsafe { ptr::drop_in_place(v.as_mut_ptr()) };
+        }
+    }
+}
+
+impl<T: Default> FromIterator<(usize, T)> for LazyVec<T> {
+    ///! Build a `LazyVec` from `(index, value)` pairs,
+    ///! writing each value at its given index in turn.
+    fn from_iter<I: IntoIterator<Item = (usize, T)>>(iter: I) -> LazyVec<T> {
+        let mut v = LazyVec::new();
+        for (i, value) in iter {
+            v[i] = value;
+        }
+        v
+    }
+}
+
+impl<T> Index<usize> for LazyVec<T> {
     type Output = T;
 
     #[inline]
@@ -144,10 +340,10 @@ impl<T: Copy> Index<usize> for LazyVec<T> {
     }
 }
 
-impl<T: Copy> IndexMut<usize> for LazyVec<T> {
+impl<T: Default> IndexMut<usize> for LazyVec<T> {
     #[inline]
     fn index_mut(&mut self, i: usize) -> &mut T {
-        unsafe{ self.value_ref_mut(i) }
+        self.value_ref_mut(i)
     }
 }
 
@@ -166,3 +362,153 @@ fn test_miss_uninit() {
     a[77] = -12i8;
     let _ = a[76];
 }
+
+#[test]
+fn test_get_miss() {
+    let mut a: LazyVec<i8> = LazyVec::new();
+    a[77] = -12i8;
+    assert_eq!(a.get(100000), None);
+    assert_eq!(a.get(76), None);
+    assert!(!a.contains_index(76));
+}
+
+#[test]
+fn test_get_hit() {
+    let mut a: LazyVec<i8> = LazyVec::new();
+    a[77] = -12i8;
+    assert_eq!(a.get(77), Some(&-12i8));
+    assert!(a.contains_index(77));
+    *a.get_mut(77).unwrap() = 5i8;
+    assert_eq!(a[77], 5i8);
+}
+
+#[test]
+fn test_iter() {
+    let mut a: LazyVec<i8> = LazyVec::new();
+    a[77] = -12i8;
+    a[3] = 9i8;
+    let collected: Vec<(usize, i8)> = a.iter().map(|(i, v)| (i, *v)).collect();
+    assert_eq!(collected, vec![(77, -12i8), (3, 9i8)]);
+}
+
+#[test]
+fn test_iter_mut() {
+    let mut a: LazyVec<i8> = LazyVec::new();
+    a[77] = -12i8;
+    for (_, v) in a.iter_mut() {
+        *v += 1;
+    }
+    assert_eq!(a[77], -11i8);
+}
+
+#[test]
+fn test_from_iter() {
+    let a: LazyVec<i8> = vec![(77usize, -12i8), (3usize, 9i8)].into_iter().collect();
+    assert_eq!(a[77], -12i8);
+    assert_eq!(a[3], 9i8);
+}
+
+#[test]
+fn test_clear() {
+    let mut a: LazyVec<i8> = LazyVec::new();
+    a[77] = -12i8;
+    a.clear();
+    assert_eq!(a.len(), 0);
+    assert_eq!(a.get(77), None);
+}
+
+#[test]
+fn test_with_default() {
+    let mut a: LazyVec<i8> = LazyVec::with_default(0i8);
+    assert_eq!(a[77], 0i8);
+    a[77] = -12i8;
+    assert_eq!(a[77], -12i8);
+    assert_eq!(a[76], 0i8);
+}
+
+#[test]
+fn test_get_with_default() {
+    let mut a: LazyVec<i8> = LazyVec::with_default(0i8);
+    // Unwritten index: get() sees the default, like Index, but
+    // contains_index() correctly reports no write happened.
+    assert_eq!(a.get(76), Some(&0i8));
+    assert!(!a.contains_index(76));
+    assert_eq!(a.get_mut(76), None);
+    // Written index: get()/get_mut()/contains_index() all see
+    // the real value.
+    a[77] = -12i8;
+    assert_eq!(a.get(77), Some(&-12i8));
+    assert!(a.contains_index(77));
+    *a.get_mut(77).unwrap() = 5i8;
+    assert_eq!(a[77], 5i8);
+}
+
+#[test]
+fn test_remove() {
+    let mut a: LazyVec<i8> = LazyVec::new();
+    a[77] = -12i8;
+    a[3] = 9i8;
+    assert_eq!(a.remove(77), Some(-12i8));
+    assert_eq!(a.get(77), None);
+    assert_eq!(a[3], 9i8);
+    assert_eq!(a.remove(77), None);
+}
+
+#[test]
+fn test_non_copy() {
+    let mut a: LazyVec<String> = LazyVec::new();
+    a[77] = "hello".to_string();
+    a[3] = "world".to_string();
+    assert_eq!(a[77], "hello");
+    assert_eq!(a.remove(3), Some("world".to_string()));
+    a.clear();
+    assert_eq!(a.len(), 0);
+}
+
+#[test]
+fn test_drop_only_written() {
+    use std::rc::Rc;
+    use std::cell::Cell;
+
+    let drops = Rc::new(Cell::new(0));
+
+    #[derive(Default)]
+    struct DropCounter(Rc<Cell<i32>>);
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    {
+        let mut a: LazyVec<DropCounter> = LazyVec::new();
+        a[77] = DropCounter(drops.clone());
+        a[3] = DropCounter(drops.clone());
+    }
+    assert_eq!(drops.get(), 2);
+}
+
+#[test]
+fn test_clear_drops() {
+    use std::rc::Rc;
+    use std::cell::Cell;
+
+    let drops = Rc::new(Cell::new(0));
+
+    #[derive(Default)]
+    struct DropCounter(Rc<Cell<i32>>);
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    let mut a: LazyVec<DropCounter> = LazyVec::new();
+    a[77] = DropCounter(drops.clone());
+    a[3] = DropCounter(drops.clone());
+    // clear()'s hand-rolled drop_in_place loop, not the
+    // container's own Drop impl, must account for both.
+    a.clear();
+    assert_eq!(drops.get(), 2);
+    assert_eq!(a.len(), 0);
+}